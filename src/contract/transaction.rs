@@ -1,26 +1,42 @@
 use crate::contract::errors::ExecutionError;
 use crate::contract::util::{CompatCallFuture, CompatSendTxWithConfirmation, Web3Unpin};
 use crate::future::MaybeReady;
-use crate::sign::TransactionData;
+use crate::sign::{GasPrice, Password, SignError, Signer, TransactionData};
 use ethsign::SecretKey;
 use futures::compat::Future01CompatExt;
 use futures::future::{self, TryFuture, TryJoin4};
 use futures::ready;
+use std::collections::{BTreeSet, HashMap};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use std::time::Duration;
 use web3::api::Web3;
 use web3::types::{
-    Address, Bytes, CallRequest, TransactionCondition, TransactionReceipt, TransactionRequest,
-    H256, U256,
+    AccessList, Address, BlockId, BlockNumber, Bytes, CallRequest, TransactionCondition,
+    TransactionReceipt, TransactionRequest, H256, U256, U64,
 };
 use web3::Transport;
 
+// NOTE: `AccessList`/`AccessListItem`, `TransactionRequest::access_list` /
+// `max_fee_per_gas` / `max_priority_fee_per_gas` / `transaction_type`,
+// `Eth::block_with_txs` and `Web3::personal` all require a newer `web3` than
+// whatever this crate is currently pinned to. This tree has no `Cargo.toml`
+// to bump (and none is added here, per repo policy against fabricating a
+// manifest in an unbuildable snapshot) — bumping the `web3` dependency and
+// confirming `cargo build` is tracked as a prerequisite for landing this
+// series for real.
+
 /// Data used for building a contract transaction that modifies the blockchain.
 /// These transactions can either be sent to be signed locally by the node or can
 /// be signed offline.
-#[derive(Clone, Debug)]
+///
+/// Breaking change: this type (and `Sign`) is no longer `Clone`. `Sign::Signer`
+/// holds a `Box<dyn Signer>` and `Sign::Locked` holds a `Password`, neither of
+/// which can be cloned, so callers that used to clone a builder or a `Sign`
+/// value need to rebuild it instead.
+#[derive(Debug)]
 pub struct TransactionBuilder<T: Transport> {
     web3: Web3<T>,
     address: Address,
@@ -31,30 +47,323 @@ pub struct TransactionBuilder<T: Transport> {
     /// Optional gas amount to use for transaction. Defaults to estimated gas.
     pub gas: Option<U256>,
     /// Optional gas price to use for transaction. Defaults to estimated gas
-    /// price.
-    pub gas_price: Option<U256>,
+    /// price. Can be a legacy gas price or an EIP-1559 fee.
+    pub gas_price: Option<GasPrice>,
     /// The ETH value to send with the transaction. Defaults to 0.
     pub value: Option<U256>,
     /// Optional nonce to use. Defaults to the signing account's current
     /// transaction count.
     pub nonce: Option<U256>,
+    /// Optional EIP-2930 access list. Defaults to no access list.
+    pub access_list: Option<AccessList>,
+    /// Optional shared nonce manager to reserve nonces from instead of
+    /// querying the node's pending transaction count. Only used when `nonce`
+    /// is not specified and offline signing is used.
+    pub nonce_manager: Option<Arc<NonceManager<T>>>,
+    /// Optional gas price estimator to use instead of the node's
+    /// `eth_gasPrice` when `gas_price` is not specified (or is
+    /// `GasPrice::Estimated`). Only used when offline signing is used;
+    /// node-signed transactions leave an unspecified gas price to the node.
+    pub gas_price_estimator: Option<Arc<GasPriceEstimator<T>>>,
 }
 
 /// How the transaction should be signed
-#[derive(Clone, Debug)]
 pub enum Sign {
     /// Let the node locally sign for address
     Local(Address, Option<TransactionCondition>),
     /// Do offline signing with private key and optionally specify chain ID
     Offline(SecretKey, Option<u64>),
+    /// Do offline signing with a pluggable `Signer` implementation (e.g. a
+    /// hardware wallet or remote signer) and optionally specify chain ID
+    Signer(Box<dyn Signer>, Option<u64>),
+    /// Let the node sign for a locked account, unlocking it for this single
+    /// call via `personal_sendTransaction`. Not supported by
+    /// `execute_and_confirm`, which fails with `SignError::Unsupported`
+    Locked(Address, Password, Option<TransactionCondition>),
+}
+
+impl std::fmt::Debug for Sign {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Sign::Local(address, condition) => {
+                f.debug_tuple("Local").field(address).field(condition).finish()
+            }
+            Sign::Offline(_, chain_id) => {
+                f.debug_tuple("Offline").field(&"..").field(chain_id).finish()
+            }
+            Sign::Signer(_, chain_id) => {
+                f.debug_tuple("Signer").field(&"..").field(chain_id).finish()
+            }
+            Sign::Locked(address, _, condition) => f
+                .debug_tuple("Locked")
+                .field(address)
+                .field(&"..")
+                .field(condition)
+                .finish(),
+        }
+    }
+}
+
+/// Hands out monotonically increasing nonces for offline signed accounts so
+/// that several transactions can be prepared concurrently without colliding
+/// on the same pending nonce.
+///
+/// The manager never hands out a nonce below the account's latest on-chain
+/// pending transaction count, so that it stays correct even across restarts.
+/// Reserved nonces that fail to be accepted by the node are returned to the
+/// pool so that a later transaction can reuse them.
+#[derive(Debug)]
+pub struct NonceManager<T: Transport> {
+    web3: Web3<T>,
+    accounts: Mutex<HashMap<Address, AccountNonces>>,
+}
+
+/// The nonce bookkeeping tracked for a single address.
+#[derive(Debug, Default)]
+struct AccountNonces {
+    /// The lowest nonce that has never been handed out before, assuming it
+    /// isn't below the on-chain pending transaction count.
+    next: U256,
+    /// Nonces that have been reserved but not yet confirmed as accepted or
+    /// rejected by the node. `reserve` never hands one of these back out.
+    in_flight: BTreeSet<U256>,
+    /// Nonces below `next` that were reserved and then released as not
+    /// accepted by the node, so they are free to be reused by a later
+    /// reservation instead of being burned forever.
+    free: BTreeSet<U256>,
+}
+
+impl<T: Transport> NonceManager<T> {
+    /// Creates a new nonce manager for the given node connection.
+    pub fn new(web3: Web3<T>) -> NonceManager<T> {
+        NonceManager {
+            web3,
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserves the next nonce to use for the given address, resynchronizing
+    /// with the node's pending transaction count so that a gap (e.g. caused
+    /// by a restart or another process using the same account) is detected
+    /// and corrected.
+    pub async fn reserve(&self, address: Address) -> Result<U256, web3::Error> {
+        let pending = self
+            .web3
+            .eth()
+            .transaction_count(address, Some(BlockNumber::Pending))
+            .compat()
+            .await?;
+
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(address).or_insert_with(AccountNonces::default);
+
+        // Resynchronize with the chain: anything below the on-chain pending
+        // count is already accounted for on-chain, so it can never be
+        // reused, in flight or not.
+        if pending > account.next {
+            account.next = pending;
+        }
+        account.free = account.free.split_off(&pending);
+
+        let mut reusable = None;
+        for nonce in account.free.iter().copied() {
+            // Shouldn't happen with correct bookkeeping, but never hand out
+            // a nonce that's still outstanding.
+            if !account.in_flight.contains(&nonce) {
+                reusable = Some(nonce);
+                break;
+            }
+        }
+
+        let reserved = match reusable {
+            Some(nonce) => {
+                account.free.remove(&nonce);
+                nonce
+            }
+            None => {
+                let nonce = account.next;
+                account.next += U256::one();
+                nonce
+            }
+        };
+
+        account.in_flight.insert(reserved);
+        Ok(reserved)
+    }
+
+    /// Releases a previously reserved nonce. If the transaction using it was
+    /// not accepted by the node, the nonce is returned to the pool so it can
+    /// be reused by the next reservation for the address.
+    pub fn release(&self, address: Address, nonce: U256, accepted: bool) {
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(account) = accounts.get_mut(&address) {
+            account.in_flight.remove(&nonce);
+            if !accepted {
+                account.free.insert(nonce);
+            }
+        }
+    }
+}
+
+/// A nonce reserved from a `NonceManager`, to be released back to the pool
+/// once the transaction using it either gets accepted or fails to dispatch
+/// (or preparation fails before it is ever dispatched).
+///
+/// Reservation happens inside a `try_join4` alongside gas/gas price/chain ID
+/// lookups, so the nonce may already have been reserved by the time one of
+/// the other legs fails; `reserved` is shared with the `NonceFuture::Reserved`
+/// that performed the reservation so the actual nonce is always available to
+/// release, regardless of which leg of the join failed.
+struct NonceReservation<T: Transport> {
+    manager: Arc<NonceManager<T>>,
+    address: Address,
+    reserved: Arc<Mutex<Option<U256>>>,
+}
+
+impl<T: Transport> NonceReservation<T> {
+    fn release(&self, accepted: bool) {
+        if let Some(nonce) = self.reserved.lock().unwrap().take() {
+            self.manager.release(self.address, nonce, accepted);
+        }
+    }
+}
+
+impl<T: Transport> Drop for NonceReservation<T> {
+    fn drop(&mut self) {
+        // Backstop for callers that never explicitly release the
+        // reservation (e.g. `TransactionBuilder::build` discards it once
+        // the prepared transaction is produced): treat it as not accepted
+        // so the nonce doesn't get stuck forever. A no-op if `release` was
+        // already called, since that clears `reserved`.
+        self.release(false);
+    }
+}
+
+/// Future resolving to the nonce to use for a transaction, either known
+/// ahead of time, fetched from the node, or reserved from a `NonceManager`.
+enum NonceFuture<T: Transport> {
+    Ready(Option<U256>),
+    Node(CompatCallFuture<T, U256>),
+    Reserved {
+        inner: Pin<Box<dyn Future<Output = Result<U256, web3::Error>> + Send>>,
+        /// Filled in with the reserved nonce as soon as it is known, even if
+        /// another leg of the surrounding `try_join4` ends up failing first.
+        reserved: Arc<Mutex<Option<U256>>>,
+    },
+}
+
+impl<T: Transport> Future for NonceFuture<T> {
+    type Output = Result<U256, web3::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.get_mut() {
+            NonceFuture::Ready(nonce) => {
+                Poll::Ready(Ok(nonce.take().expect("polled after completion")))
+            }
+            NonceFuture::Node(inner) => Pin::new(inner).poll(cx),
+            NonceFuture::Reserved { inner, reserved } => match inner.as_mut().poll(cx) {
+                Poll::Ready(Ok(nonce)) => {
+                    *reserved.lock().unwrap() = Some(nonce);
+                    Poll::Ready(Ok(nonce))
+                }
+                other => other,
+            },
+        }
+    }
+}
+
+/// Estimates a gas price from a corpus of recent blocks' transaction gas
+/// prices, rather than relying on the node's possibly stale or overly
+/// conservative `eth_gasPrice` response.
+#[derive(Debug)]
+pub struct GasPriceEstimator<T: Transport> {
+    web3: Web3<T>,
+    /// The number of most recent blocks to sample transactions from.
+    blocks: u64,
+    /// The percentile (0-100) of the sorted corpus to use as the estimate,
+    /// e.g. the 60th percentile for "fast" or the 50th (median) for
+    /// "standard".
+    percentile: usize,
+}
+
+impl<T: Transport> GasPriceEstimator<T> {
+    /// Creates a new gas price estimator sampling `blocks` most recent
+    /// blocks and returning the given `percentile` of the resulting corpus.
+    pub fn new(web3: Web3<T>, blocks: u64, percentile: usize) -> GasPriceEstimator<T> {
+        GasPriceEstimator {
+            web3,
+            blocks,
+            percentile,
+        }
+    }
+
+    /// Estimates a gas price from the configured window of recent blocks,
+    /// falling back to the node's `eth_gasPrice` if the corpus ends up
+    /// empty (e.g. the sampled blocks have no transactions).
+    pub async fn estimate(&self) -> Result<U256, web3::Error> {
+        let eth = self.web3.eth();
+        let latest = eth.block_number().compat().await?.as_u64();
+        let first = (latest + 1).saturating_sub(self.blocks);
+
+        let blocks = future::try_join_all((first..=latest).map(|number| {
+            eth.block_with_txs(BlockId::Number(BlockNumber::Number(number.into())))
+                .compat()
+        }))
+        .await?;
+
+        let mut corpus: Vec<U256> = blocks
+            .into_iter()
+            .flatten()
+            .flat_map(|block| block.transactions)
+            .filter_map(|tx| tx.gas_price)
+            .collect();
+
+        if corpus.is_empty() {
+            return eth.gas_price().compat().await;
+        }
+
+        corpus.sort();
+        let index = (corpus.len() * self.percentile) / 100;
+        Ok(corpus[index.min(corpus.len() - 1)])
+    }
+}
+
+/// Future resolving to the gas price to use for a transaction, either known
+/// ahead of time, fetched from the node, or computed by a
+/// `GasPriceEstimator`.
+enum GasPriceFuture<T: Transport> {
+    Ready(Option<U256>),
+    Node(CompatCallFuture<T, U256>),
+    Estimated(Pin<Box<dyn Future<Output = Result<U256, web3::Error>> + Send>>),
+}
+
+impl<T: Transport> Future for GasPriceFuture<T> {
+    type Output = Result<U256, web3::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.get_mut() {
+            GasPriceFuture::Ready(gas_price) => {
+                Poll::Ready(Ok(gas_price.take().expect("polled after completion")))
+            }
+            GasPriceFuture::Node(inner) => Pin::new(inner).poll(cx),
+            GasPriceFuture::Estimated(inner) => inner.as_mut().poll(cx),
+        }
+    }
 }
 
-/// Represents either a structured or raw transaction request.
-enum Request {
+/// A finalized transaction, ready to be dispatched to the node. Obtained by
+/// calling `TransactionBuilder::build` without broadcasting it, e.g. to
+/// inspect, log, or hand off to a relayer.
+#[derive(Debug)]
+pub enum PreparedTransaction {
     /// A structured transaction request to be signed locally by the node.
     Tx(TransactionRequest),
     /// A signed raw transaction request.
     Raw(Bytes),
+    /// A structured transaction request to be signed by the node for an
+    /// account that is locked by default, unlocked for this call only with
+    /// the given password.
+    Locked(TransactionRequest, Password),
 }
 
 impl<T: Transport> TransactionBuilder<T> {
@@ -68,6 +377,9 @@ impl<T: Transport> TransactionBuilder<T> {
             gas_price: None,
             value: None,
             nonce: None,
+            access_list: None,
+            nonce_manager: None,
+            gas_price_estimator: None,
             sign: None,
         }
     }
@@ -87,9 +399,10 @@ impl<T: Transport> TransactionBuilder<T> {
     }
 
     /// Specify the gas price to use, if not specified then the estimated gas
-    /// price will be used.
-    pub fn gas_price(mut self, value: U256) -> TransactionBuilder<T> {
-        self.gas_price = Some(value);
+    /// price will be used. Accepts either a legacy gas price or an EIP-1559
+    /// fee via `GasPrice::Eip1559`.
+    pub fn gas_price(mut self, value: impl Into<GasPrice>) -> TransactionBuilder<T> {
+        self.gas_price = Some(value.into());
         self
     }
 
@@ -107,6 +420,55 @@ impl<T: Transport> TransactionBuilder<T> {
         self
     }
 
+    /// Specify the access list to use for the transaction, if not specified
+    /// then no access list is used. Pre-declaring the storage slots and
+    /// addresses a contract call touches can make it cheaper on chains that
+    /// support EIP-2930.
+    pub fn access_list(mut self, value: AccessList) -> TransactionBuilder<T> {
+        self.access_list = Some(value);
+        self
+    }
+
+    /// Use the given `NonceManager` to reserve a nonce for this transaction
+    /// instead of querying the node's pending transaction count, if `nonce`
+    /// is not otherwise specified. Useful for dispatching several
+    /// transactions for the same offline account in quick succession.
+    pub fn nonce_manager(mut self, value: Arc<NonceManager<T>>) -> TransactionBuilder<T> {
+        self.nonce_manager = Some(value);
+        self
+    }
+
+    /// Use the given `GasPriceEstimator` to price this transaction instead
+    /// of the node's `eth_gasPrice`, if `gas_price` is not otherwise
+    /// specified. Only used when offline signing is used; node-signed
+    /// transactions leave an unspecified gas price to the node.
+    pub fn gas_price_estimator(
+        mut self,
+        value: Arc<GasPriceEstimator<T>>,
+    ) -> TransactionBuilder<T> {
+        self.gas_price_estimator = Some(value);
+        self
+    }
+
+    /// Sign (if required) the transaction and return the finalized,
+    /// prepared transaction without broadcasting it. This is useful for
+    /// workflows like offline signing on an air-gapped machine, transaction
+    /// batching, inspection or logging before submission, or handing the
+    /// raw bytes off to a relayer.
+    ///
+    /// Note: if a `NonceManager` was used, the reservation is released back
+    /// to the pool as soon as this future resolves, since `build` has no way
+    /// to know whether or when the returned `PreparedTransaction` will
+    /// actually be dispatched. This means a nonce handed out by `build` can
+    /// be reused by a later `build`/`execute`/`execute_and_confirm` call for
+    /// the same account before the first transaction is ever broadcast;
+    /// callers that need the nonce to stay reserved until dispatch should use
+    /// `execute`/`execute_and_confirm` instead, which hold the reservation
+    /// until the transaction is actually sent.
+    pub async fn build(self) -> Result<PreparedTransaction, ExecutionError> {
+        PrepareFuture::from_builder(self).await.map(|(tx, _reservation)| tx)
+    }
+
     /// Sign (if required) and execute the transaction. Returns the transaction
     /// hash that can be used to retrieve transaction information.
     pub fn execute(self) -> ExecuteFuture<T> {
@@ -127,7 +489,7 @@ impl<T: Transport> TransactionBuilder<T> {
 /// Internal future for preparing a transaction for sending.
 enum PrepareFuture<T: Transport> {
     /// Waiting for list of accounts in order to determine from address so that
-    /// we can return a `Request::Tx`.
+    /// we can return a `PreparedTransaction::Tx`.
     TxDefaultAccount {
         /// The transaction request being built.
         request: Option<TransactionRequest>,
@@ -136,17 +498,25 @@ enum PrepareFuture<T: Transport> {
         inner: CompatCallFuture<T, Vec<Address>>,
     },
 
-    /// Ready to produce a `Request::Tx` result.
+    /// Ready to produce a `PreparedTransaction::Tx` result.
     Tx {
         /// The ready transaction request.
         request: Option<TransactionRequest>,
     },
 
+    /// Ready to produce a `PreparedTransaction::Locked` result.
+    Locked {
+        /// The ready transaction request and the password to unlock the
+        /// account with for this call.
+        request: Option<(TransactionRequest, Password)>,
+    },
+
     /// Waiting for missing transaction parameters needed to sign and produce a
-    /// `Request::Raw` result.
+    /// `PreparedTransaction::Raw` result.
     Raw {
-        /// The private key to use for signing.
-        key: SecretKey,
+        /// The signer used to sign the transaction once all its parameters
+        /// are known.
+        signer: Arc<dyn Signer>,
 
         /// The contract address.
         address: Address,
@@ -157,142 +527,341 @@ enum PrepareFuture<T: Transport> {
         /// The ABI encoded call parameters,
         data: Bytes,
 
+        /// The EIP-2930 access list to include with the transaction, if any.
+        access_list: Option<AccessList>,
+
+        /// The EIP-1559 fee fields to use, if specified, in place of the
+        /// legacy gas price fetched as part of `params`.
+        eip1559: Option<(U256, U256)>,
+
+        /// The nonce reservation to release if preparation fails, if the
+        /// nonce was obtained from a `NonceManager`.
+        reservation: Option<NonceReservation<T>>,
+
         /// Future for retrieving gas, gas price, nonce and chain ID when they
         /// where not specified.
         params: TryJoin4<
             MaybeReady<CompatCallFuture<T, U256>>,
-            MaybeReady<CompatCallFuture<T, U256>>,
-            MaybeReady<CompatCallFuture<T, U256>>,
+            GasPriceFuture<T>,
+            NonceFuture<T>,
             MaybeReady<CompatCallFuture<T, String>>,
         >,
     },
+
+    /// Waiting for the `Signer` to produce the raw, signed transaction bytes.
+    Sign {
+        future: Pin<Box<dyn Future<Output = Result<Bytes, SignError>> + Send>>,
+
+        /// The nonce reservation to release once the signed bytes are
+        /// produced (on success it is handed off to the caller so it can be
+        /// released once the transaction is dispatched; on failure it is
+        /// released immediately since the transaction never gets sent).
+        reservation: Option<NonceReservation<T>>,
+    },
+}
+
+/// The gas pricing fields to set on a node-signed `TransactionRequest`,
+/// derived from a `GasPrice`. Populating the EIP-1559 fields (instead of
+/// just falling back to a legacy `gas_price`) lets the node sign a type-2
+/// transaction that actually carries the caller's fee caps.
+#[derive(Default)]
+struct RequestGasPrice {
+    gas_price: Option<U256>,
+    max_fee_per_gas: Option<U256>,
+    max_priority_fee_per_gas: Option<U256>,
+    transaction_type: Option<U64>,
+}
+
+fn request_gas_price(gas_price: &Option<GasPrice>) -> RequestGasPrice {
+    match gas_price {
+        None | Some(GasPrice::Estimated) => RequestGasPrice::default(),
+        Some(GasPrice::Legacy(value)) => RequestGasPrice {
+            gas_price: Some(*value),
+            ..RequestGasPrice::default()
+        },
+        Some(GasPrice::Eip1559 {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        }) => RequestGasPrice {
+            max_fee_per_gas: Some(*max_fee_per_gas),
+            max_priority_fee_per_gas: Some(*max_priority_fee_per_gas),
+            transaction_type: Some(U64::from(2)),
+            ..RequestGasPrice::default()
+        },
+    }
 }
 
 impl<T: Transport> PrepareFuture<T> {
     /// Create a `PrepareFuture` from a `TransactionBuilder`
-    fn from_builder(builder: TransactionBuilder<T>) -> PrepareFuture<T> {
-        match builder.sign {
-            None => PrepareFuture::TxDefaultAccount {
-                request: Some(TransactionRequest {
-                    from: Address::zero(),
-                    to: Some(builder.address),
-                    gas: builder.gas,
-                    gas_price: builder.gas_price,
-                    value: builder.value,
-                    data: Some(builder.data),
-                    nonce: builder.nonce,
-                    condition: None,
-                }),
-                inner: builder.web3.eth().accounts().compat(),
-            },
-            Some(Sign::Local(from, condition)) => PrepareFuture::Tx {
-                request: Some(TransactionRequest {
-                    from,
-                    to: Some(builder.address),
-                    gas: builder.gas,
-                    gas_price: builder.gas_price,
-                    value: builder.value,
-                    data: Some(builder.data),
-                    nonce: builder.nonce,
-                    condition,
-                }),
-            },
-            Some(Sign::Offline(key, chain_id)) => {
-                macro_rules! maybe {
-                    ($o:expr, $c:expr) => {
-                        match $o {
-                            Some(v) => MaybeReady::ready(Ok(v)),
-                            None => MaybeReady::future($c.compat()),
-                        }
-                    };
+    fn from_builder(mut builder: TransactionBuilder<T>) -> PrepareFuture<T> {
+        match builder.sign.take() {
+            None => {
+                let gas_price = request_gas_price(&builder.gas_price);
+                PrepareFuture::TxDefaultAccount {
+                    request: Some(TransactionRequest {
+                        from: Address::zero(),
+                        to: Some(builder.address),
+                        gas: builder.gas,
+                        gas_price: gas_price.gas_price,
+                        value: builder.value,
+                        data: Some(builder.data),
+                        nonce: builder.nonce,
+                        condition: None,
+                        access_list: builder.access_list,
+                        max_fee_per_gas: gas_price.max_fee_per_gas,
+                        max_priority_fee_per_gas: gas_price.max_priority_fee_per_gas,
+                        transaction_type: gas_price.transaction_type,
+                    }),
+                    inner: builder.web3.eth().accounts().compat(),
                 }
-
-                let from = key.public().address().into();
-                let eth = builder.web3.eth();
-                let net = builder.web3.net();
-
-                let gas = maybe!(
-                    builder.gas,
-                    eth.estimate_gas(
-                        CallRequest {
-                            from: Some(from),
-                            to: builder.address,
-                            gas: None,
-                            gas_price: None,
+            }
+            Some(Sign::Local(from, condition)) => {
+                let gas_price = request_gas_price(&builder.gas_price);
+                PrepareFuture::Tx {
+                    request: Some(TransactionRequest {
+                        from,
+                        to: Some(builder.address),
+                        gas: builder.gas,
+                        gas_price: gas_price.gas_price,
+                        value: builder.value,
+                        data: Some(builder.data),
+                        nonce: builder.nonce,
+                        condition,
+                        access_list: builder.access_list,
+                        max_fee_per_gas: gas_price.max_fee_per_gas,
+                        max_priority_fee_per_gas: gas_price.max_priority_fee_per_gas,
+                        transaction_type: gas_price.transaction_type,
+                    }),
+                }
+            }
+            Some(Sign::Offline(key, chain_id)) => {
+                PrepareFuture::raw(builder, Arc::new(key), chain_id)
+            }
+            Some(Sign::Signer(signer, chain_id)) => {
+                PrepareFuture::raw(builder, Arc::from(signer), chain_id)
+            }
+            Some(Sign::Locked(from, password, condition)) => {
+                let gas_price = request_gas_price(&builder.gas_price);
+                PrepareFuture::Locked {
+                    request: Some((
+                        TransactionRequest {
+                            from,
+                            to: Some(builder.address),
+                            gas: builder.gas,
+                            gas_price: gas_price.gas_price,
                             value: builder.value,
-                            data: Some(builder.data.clone()),
+                            data: Some(builder.data),
+                            nonce: builder.nonce,
+                            condition,
+                            access_list: builder.access_list,
+                            max_fee_per_gas: gas_price.max_fee_per_gas,
+                            max_priority_fee_per_gas: gas_price.max_priority_fee_per_gas,
+                            transaction_type: gas_price.transaction_type,
                         },
-                        None
-                    )
-                );
+                        password,
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Builds a `PrepareFuture::Raw` state that signs the transaction with
+    /// the given `Signer` once gas, gas price, nonce and chain ID are known.
+    fn raw(
+        builder: TransactionBuilder<T>,
+        signer: Arc<dyn Signer>,
+        chain_id: Option<u64>,
+    ) -> PrepareFuture<T> {
+        macro_rules! maybe {
+            ($o:expr, $c:expr) => {
+                match $o {
+                    Some(v) => MaybeReady::ready(Ok(v)),
+                    None => MaybeReady::future($c.compat()),
+                }
+            };
+        }
 
-                let gas_price = maybe!(builder.gas_price, eth.gas_price());
-                let nonce = maybe!(builder.nonce, eth.transaction_count(from, None));
+        let from = signer.address();
+        let eth = builder.web3.eth();
+        let net = builder.web3.net();
 
-                // it looks like web3 defaults chain ID to network ID, although
-                // this is not 'correct' in all cases it does work for most cases
-                // like mainnet and various testnets and provides better safety
-                // against replay attacks then just using no chain ID; so lets
-                // reproduce that behaviour here
-                // TODO(nlordell): don't convert to and from string here
-                let chain_id = maybe!(chain_id.map(|id| id.to_string()), net.version());
+        let gas = maybe!(
+            builder.gas,
+            eth.estimate_gas(
+                CallRequest {
+                    from: Some(from),
+                    to: builder.address,
+                    gas: None,
+                    gas_price: None,
+                    value: builder.value,
+                    data: Some(builder.data.clone()),
+                },
+                None
+            )
+        );
 
-                PrepareFuture::Raw {
-                    key,
-                    address: builder.address,
-                    value: builder.value.unwrap_or_else(U256::zero),
-                    data: builder.data,
-                    params: future::try_join4(gas, gas_price, nonce, chain_id),
+        let eip1559 = match builder.gas_price {
+            Some(GasPrice::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            }) => Some((max_fee_per_gas, max_priority_fee_per_gas)),
+            _ => None,
+        };
+        let legacy_gas_price = match builder.gas_price {
+            Some(GasPrice::Legacy(value)) => Some(value),
+            Some(GasPrice::Eip1559 { .. }) => Some(U256::zero()),
+            Some(GasPrice::Estimated) | None => None,
+        };
+        let gas_price = match (legacy_gas_price, &builder.gas_price_estimator) {
+            (Some(value), _) => GasPriceFuture::Ready(Some(value)),
+            (None, Some(estimator)) => {
+                let estimator = Arc::clone(estimator);
+                GasPriceFuture::Estimated(Box::pin(async move { estimator.estimate().await }))
+            }
+            (None, None) => GasPriceFuture::Node(eth.gas_price().compat()),
+        };
+        let mut reservation = None;
+        let nonce = match (builder.nonce, &builder.nonce_manager) {
+            (Some(nonce), _) => NonceFuture::Ready(Some(nonce)),
+            (None, Some(manager)) => {
+                let manager = Arc::clone(manager);
+                let reserved = Arc::new(Mutex::new(None));
+                reservation = Some(NonceReservation {
+                    manager: Arc::clone(&manager),
+                    address: from,
+                    reserved: Arc::clone(&reserved),
+                });
+                NonceFuture::Reserved {
+                    inner: Box::pin(async move { manager.reserve(from).await }),
+                    reserved,
                 }
             }
+            (None, None) => NonceFuture::Node(eth.transaction_count(from, None).compat()),
+        };
+
+        // it looks like web3 defaults chain ID to network ID, although
+        // this is not 'correct' in all cases it does work for most cases
+        // like mainnet and various testnets and provides better safety
+        // against replay attacks then just using no chain ID; so lets
+        // reproduce that behaviour here
+        // TODO(nlordell): don't convert to and from string here
+        let chain_id = maybe!(chain_id.map(|id| id.to_string()), net.version());
+
+        PrepareFuture::Raw {
+            signer,
+            address: builder.address,
+            value: builder.value.unwrap_or_else(U256::zero),
+            data: builder.data,
+            access_list: builder.access_list,
+            eip1559,
+            reservation,
+            params: future::try_join4(gas, gas_price, nonce, chain_id),
         }
     }
 }
 
 impl<T: Transport> Future for PrepareFuture<T> {
-    type Output = Result<Request, ExecutionError>;
+    /// The prepared transaction, along with the nonce reservation (if any)
+    /// that the caller is now responsible for releasing once the
+    /// transaction is dispatched (or not, if it never gets sent).
+    type Output = Result<(PreparedTransaction, Option<NonceReservation<T>>), ExecutionError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let unpinned = self.get_mut();
-        match unpinned {
-            PrepareFuture::TxDefaultAccount { request, inner } => {
-                Pin::new(inner).poll(cx).map(|accounts| {
-                    let accounts = accounts?;
-                    let mut request = request.take().expect("should be called only once");
-
-                    if let Some(from) = accounts.get(0) {
-                        request.from = *from;
-                    }
+        loop {
+            match unpinned {
+                PrepareFuture::TxDefaultAccount { request, inner } => {
+                    return Pin::new(inner).poll(cx).map(|accounts| {
+                        let accounts = accounts?;
+                        let mut request = request.take().expect("should be called only once");
 
-                    Ok(Request::Tx(request))
-                })
-            }
-            PrepareFuture::Tx { request } => {
-                let request = request.take().expect("should be called only once");
-                Poll::Ready(Ok(Request::Tx(request)))
+                        if let Some(from) = accounts.get(0) {
+                            request.from = *from;
+                        }
+
+                        Ok((PreparedTransaction::Tx(request), None))
+                    });
+                }
+                PrepareFuture::Tx { request } => {
+                    let request = request.take().expect("should be called only once");
+                    return Poll::Ready(Ok((PreparedTransaction::Tx(request), None)));
+                }
+                PrepareFuture::Locked { request } => {
+                    let (request, password) = request.take().expect("should be called only once");
+                    return Poll::Ready(Ok((PreparedTransaction::Locked(request, password), None)));
+                }
+                PrepareFuture::Raw {
+                    signer,
+                    address,
+                    value,
+                    data,
+                    access_list,
+                    eip1559,
+                    reservation,
+                    params,
+                } => {
+                    let result = ready!(Pin::new(params).poll(cx));
+                    let result = result.map_err(ExecutionError::from).and_then(|result| {
+                        let (gas, gas_price, nonce, chain_id) = result;
+                        let chain_id: u64 = chain_id.parse()?;
+
+                        let gas_price = match eip1559 {
+                            Some((max_fee_per_gas, max_priority_fee_per_gas)) => {
+                                GasPrice::Eip1559 {
+                                    max_fee_per_gas: *max_fee_per_gas,
+                                    max_priority_fee_per_gas: *max_priority_fee_per_gas,
+                                }
+                            }
+                            None => GasPrice::Legacy(gas_price),
+                        };
+
+                        Ok((
+                            TransactionData {
+                                nonce,
+                                gas_price,
+                                gas,
+                                to: *address,
+                                value: *value,
+                                data: data.clone(),
+                                access_list: access_list.clone(),
+                            },
+                            chain_id,
+                        ))
+                    });
+
+                    match result {
+                        Ok((tx, chain_id)) => {
+                            let signer = Arc::clone(signer);
+                            *unpinned = PrepareFuture::Sign {
+                                future: Box::pin(async move {
+                                    signer.sign_transaction(tx, Some(chain_id)).await
+                                }),
+                                reservation: reservation.take(),
+                            };
+                        }
+                        Err(err) => {
+                            // preparation failed before the transaction could
+                            // be signed or dispatched; release the nonce so a
+                            // later transaction doesn't get stuck behind it.
+                            if let Some(reservation) = reservation.take() {
+                                reservation.release(false);
+                            }
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                }
+                PrepareFuture::Sign { future, reservation } => {
+                    return future.as_mut().poll(cx).map(|result| match result {
+                        Ok(bytes) => Ok((PreparedTransaction::Raw(bytes), reservation.take())),
+                        Err(err) => {
+                            if let Some(reservation) = reservation.take() {
+                                reservation.release(false);
+                            }
+                            Err(ExecutionError::from(err))
+                        }
+                    });
+                }
             }
-            PrepareFuture::Raw {
-                key,
-                address,
-                value,
-                data,
-                params,
-            } => Pin::new(params).poll(cx).map(|result| {
-                let (gas, gas_price, nonce, chain_id) = result?;
-                let chain_id = chain_id.parse()?;
-
-                let tx = TransactionData {
-                    nonce,
-                    gas_price,
-                    gas,
-                    to: *address,
-                    value: *value,
-                    data: data,
-                };
-                let raw = tx.sign(key, Some(chain_id))?;
-
-                Ok(Request::Raw(raw))
-            }),
         }
     }
 }
@@ -323,9 +892,14 @@ impl<T: Transport> Future for ExecuteFuture<T> {
     type Output = Result<H256, ExecutionError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        self.state().poll_with_send(cx, |web3, tx| match tx {
-            Request::Tx(tx) => web3.eth().send_transaction(tx).compat(),
-            Request::Raw(tx) => web3.eth().send_raw_transaction(tx).compat(),
+        self.state().poll_with_send(cx, |web3, tx| {
+            Ok(match tx {
+                PreparedTransaction::Tx(tx) => web3.eth().send_transaction(tx).compat(),
+                PreparedTransaction::Raw(tx) => web3.eth().send_raw_transaction(tx).compat(),
+                PreparedTransaction::Locked(tx, password) => {
+                    web3.personal().send_transaction(tx, password.as_str()).compat()
+                }
+            })
         })
     }
 }
@@ -373,12 +947,24 @@ impl<T: Transport> Future for ExecuteConfirmFuture<T> {
         self.as_mut().state().poll_with_send(cx, |web3, tx| {
             let (poll_interval, confirmations) = confirm;
             match tx {
-                Request::Tx(tx) => web3
+                PreparedTransaction::Tx(tx) => Ok(web3
                     .send_transaction_with_confirmation(tx, poll_interval, confirmations)
-                    .compat(),
-                Request::Raw(tx) => web3
+                    .compat()),
+                PreparedTransaction::Raw(tx) => Ok(web3
                     .send_raw_transaction_with_confirmation(tx, poll_interval, confirmations)
-                    .compat(),
+                    .compat()),
+                // `web3`'s `personal` namespace has no "with confirmation"
+                // variant, so there is no safe way to unlock the account for
+                // just this call and still wait for a receipt here. Sending
+                // a plain (unauthenticated) `eth_sendTransaction` instead
+                // would silently bypass the lock this signing strategy
+                // exists for, so fail explicitly rather than dispatch
+                // unauthorized.
+                PreparedTransaction::Locked(..) => Err(ExecutionError::from(SignError::Unsupported(
+                    "confirmed execution is not supported for password-unlocked (`Sign::Locked`) \
+                     transactions; use `execute()` without confirmation, or sign with a `Signer` \
+                     or offline key instead",
+                ))),
             }
         })
     }
@@ -392,7 +978,7 @@ where
     F::Error: Into<ExecutionError>,
 {
     Prepare(PrepareFuture<T>, Web3Unpin<T>),
-    Send(F),
+    Send(F, Option<NonceReservation<T>>),
 }
 
 impl<T, F> ExecutionState<T, F>
@@ -407,21 +993,34 @@ where
         mut send_fn: S,
     ) -> Poll<Result<F::Ok, ExecutionError>>
     where
-        S: FnMut(&Web3<T>, Request) -> F,
+        S: FnMut(&Web3<T>, PreparedTransaction) -> Result<F, ExecutionError>,
     {
         loop {
             match self {
                 ExecutionState::Prepare(ref mut prepare, web3) => {
-                    let tx = ready!(Pin::new(prepare).poll(cx).map_err(ExecutionError::from));
-                    let send = match tx {
-                        Ok(tx) => send_fn(&*web3, tx),
+                    let (tx, reservation) = match ready!(Pin::new(prepare).poll(cx)) {
+                        Ok(result) => result,
                         Err(e) => return Poll::Ready(Err(e)),
                     };
-
-                    *self = ExecutionState::Send(send);
+                    match send_fn(&*web3, tx) {
+                        Ok(send) => *self = ExecutionState::Send(send, reservation),
+                        Err(e) => {
+                            // the prepared transaction was never actually
+                            // dispatched, so release the nonce immediately
+                            // instead of leaking it.
+                            if let Some(reservation) = reservation {
+                                reservation.release(false);
+                            }
+                            return Poll::Ready(Err(e));
+                        }
+                    }
                 }
-                ExecutionState::Send(ref mut send) => {
-                    return Pin::new(send).try_poll(cx).map_err(Into::into)
+                ExecutionState::Send(ref mut send, reservation) => {
+                    let result = ready!(Pin::new(send).try_poll(cx));
+                    if let Some(reservation) = reservation.take() {
+                        reservation.release(result.is_ok());
+                    }
+                    return Poll::Ready(result.map_err(Into::into));
                 }
             }
         }
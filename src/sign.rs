@@ -0,0 +1,409 @@
+//! Module implementing transaction signing for offline accounts.
+
+use crate::contract::errors::ExecutionError;
+use ethsign::{Signature, SecretKey};
+use rlp::RlpStream;
+use tiny_keccak::{Hasher, Keccak};
+use web3::types::{AccessList, Address, Bytes, U256};
+
+/// A password used to unlock a node-managed account for a single
+/// `personal_sendTransaction`/`personal_signTransaction` call. The
+/// underlying memory is scrubbed when the password is dropped.
+pub struct Password(String);
+
+impl Password {
+    /// Wraps the given password so that its memory is scrubbed on drop.
+    pub fn new(password: impl Into<String>) -> Password {
+        Password(password.into())
+    }
+
+    /// Returns the password as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        // SAFETY: overwriting every byte with `0` keeps the string valid
+        // UTF-8. Each byte is written with a volatile store, and a compiler
+        // fence follows the loop, so the optimizer can't prove the writes
+        // are dead (nothing reads `self.0` afterwards) and elide them.
+        //
+        // `Password` exposes no API to mutate the string after construction,
+        // so its buffer is never reallocated during its lifetime; this scrubs
+        // the only copy of the password that ever existed in this wrapper.
+        for byte in unsafe { self.0.as_bytes_mut() } {
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// The gas price strategy to use when preparing a transaction for signing.
+///
+/// This replaces a bare `gas_price: Option<U256>` so that transactions can
+/// either use the legacy single gas price fee model or the EIP-1559 fee
+/// market introduced in the London hard fork.
+#[derive(Clone, Debug)]
+pub enum GasPrice {
+    /// Use an estimated gas price, resolved when a `TransactionBuilder`
+    /// prepares the transaction (from its `GasPriceEstimator` if one is set,
+    /// otherwise the node's `eth_gasPrice`). `TransactionData::sign` does not
+    /// resolve it and encodes a gas price of zero.
+    Estimated,
+    /// A legacy, pre-EIP-1559 gas price.
+    Legacy(U256),
+    /// An EIP-1559 transaction fee, specifying the maximum total fee per gas
+    /// the sender is willing to pay and the maximum tip given to the block
+    /// proposer.
+    Eip1559 {
+        /// The maximum total fee per gas (base fee + tip).
+        max_fee_per_gas: U256,
+        /// The maximum tip per gas paid to the block's proposer.
+        max_priority_fee_per_gas: U256,
+    },
+}
+
+impl Default for GasPrice {
+    fn default() -> Self {
+        GasPrice::Estimated
+    }
+}
+
+impl From<U256> for GasPrice {
+    fn from(value: U256) -> Self {
+        GasPrice::Legacy(value)
+    }
+}
+
+/// Data used to construct a signed raw transaction for offline signing.
+#[derive(Clone, Debug)]
+pub struct TransactionData {
+    /// The signing account's nonce to prevent replay attacks.
+    pub nonce: U256,
+    /// The gas price (legacy or EIP-1559) to use for the transaction.
+    pub gas_price: GasPrice,
+    /// The amount of gas to use for the transaction.
+    pub gas: U256,
+    /// The address of the contract to invoke.
+    pub to: Address,
+    /// The ETH value to send with the transaction.
+    pub value: U256,
+    /// The ABI encoded call parameters.
+    pub data: Bytes,
+    /// The EIP-2930 access list of addresses and storage slots the
+    /// transaction expects to access, if any.
+    pub access_list: Option<AccessList>,
+}
+
+/// Error that can occur while signing a transaction.
+#[derive(Debug)]
+pub enum SignError {
+    /// An error occurred computing the recoverable signature.
+    Signing(ethsign::Error),
+    /// The requested operation is not supported for the signing strategy in
+    /// use (e.g. waiting for confirmation on a password-unlocked account).
+    Unsupported(&'static str),
+}
+
+impl std::fmt::Display for SignError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SignError::Signing(err) => write!(f, "failed to sign transaction: {}", err),
+            SignError::Unsupported(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for SignError {}
+
+impl From<ethsign::Error> for SignError {
+    fn from(err: ethsign::Error) -> Self {
+        SignError::Signing(err)
+    }
+}
+
+impl From<SignError> for ExecutionError {
+    fn from(err: SignError) -> Self {
+        ExecutionError::Sign(err)
+    }
+}
+
+/// A signer capable of producing a raw, signed transaction for its address.
+///
+/// This allows the signing account's private key material to live outside of
+/// the crate, for example on a hardware wallet or a remote KMS-backed signer.
+#[async_trait::async_trait]
+pub trait Signer: Send + Sync {
+    /// The address that this signer signs transactions for.
+    fn address(&self) -> Address;
+
+    /// Sign the given transaction data, returning the raw, RLP encoded,
+    /// signed transaction bytes ready to be broadcast with
+    /// `eth_sendRawTransaction`.
+    async fn sign_transaction(
+        &self,
+        tx: TransactionData,
+        chain_id: Option<u64>,
+    ) -> Result<Bytes, SignError>;
+}
+
+#[async_trait::async_trait]
+impl Signer for SecretKey {
+    fn address(&self) -> Address {
+        self.public().address().into()
+    }
+
+    async fn sign_transaction(
+        &self,
+        tx: TransactionData,
+        chain_id: Option<u64>,
+    ) -> Result<Bytes, SignError> {
+        tx.sign(self, chain_id)
+    }
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut output = [0u8; 32];
+    let mut hasher = Keccak::v256();
+    hasher.update(data);
+    hasher.finalize(&mut output);
+    output
+}
+
+fn append_legacy_v(stream: &mut RlpStream, signature: &Signature, chain_id: Option<u64>) {
+    let v = match chain_id {
+        Some(chain_id) => u64::from(signature.v) + 35 + chain_id * 2,
+        None => u64::from(signature.v) + 27,
+    };
+    stream.append(&v);
+}
+
+fn append_access_list(stream: &mut RlpStream, access_list: &AccessList) {
+    stream.begin_list(access_list.len());
+    for item in access_list {
+        stream.begin_list(2);
+        stream.append(&item.address);
+        stream.begin_list(item.storage_keys.len());
+        for key in &item.storage_keys {
+            stream.append(key);
+        }
+    }
+}
+
+fn append_signature(stream: &mut RlpStream, signature: &Signature) {
+    stream.append(&signature.v);
+    stream.append(&signature.r.as_ref());
+    stream.append(&signature.s.as_ref());
+}
+
+impl TransactionData {
+    /// Appends the common fields shared by legacy and EIP-2930 transactions
+    /// (nonce through data) to the given RLP stream.
+    fn append_body(&self, stream: &mut RlpStream, gas_price: U256) {
+        stream.append(&self.nonce);
+        stream.append(&gas_price);
+        stream.append(&self.gas);
+        stream.append(&self.to);
+        stream.append(&self.value);
+        stream.append(&self.data.0);
+    }
+
+    fn legacy_gas_price(&self) -> U256 {
+        match &self.gas_price {
+            GasPrice::Legacy(gas_price) => *gas_price,
+            GasPrice::Estimated => U256::zero(),
+            GasPrice::Eip1559 { .. } => U256::zero(),
+        }
+    }
+
+    /// RLP encode the transaction, optionally appending the given signature,
+    /// and prefixing the result with the EIP-2718 transaction type byte for
+    /// typed (EIP-2930 or EIP-1559) transactions.
+    fn rlp_encode(&self, chain_id: Option<u64>, signature: Option<&Signature>) -> Vec<u8> {
+        let legacy_chain_id = chain_id;
+        let chain_id = chain_id.unwrap_or_default();
+        let access_list = self.access_list.clone().unwrap_or_default();
+
+        match &self.gas_price {
+            GasPrice::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => {
+                let mut stream = RlpStream::new();
+                stream.begin_list(if signature.is_some() { 12 } else { 9 });
+                stream.append(&chain_id);
+                stream.append(&self.nonce);
+                stream.append(max_priority_fee_per_gas);
+                stream.append(max_fee_per_gas);
+                stream.append(&self.gas);
+                stream.append(&self.to);
+                stream.append(&self.value);
+                stream.append(&self.data.0);
+                append_access_list(&mut stream, &access_list);
+                if let Some(signature) = signature {
+                    append_signature(&mut stream, signature);
+                }
+
+                let mut raw = vec![0x02];
+                raw.extend_from_slice(stream.as_raw());
+                raw
+            }
+            _ if self.access_list.is_some() => {
+                let mut stream = RlpStream::new();
+                stream.begin_list(if signature.is_some() { 11 } else { 8 });
+                stream.append(&chain_id);
+                self.append_body(&mut stream, self.legacy_gas_price());
+                append_access_list(&mut stream, &access_list);
+                if let Some(signature) = signature {
+                    append_signature(&mut stream, signature);
+                }
+
+                let mut raw = vec![0x01];
+                raw.extend_from_slice(stream.as_raw());
+                raw
+            }
+            _ => {
+                let mut stream = RlpStream::new();
+                // without a chain ID the unsigned form is the pre-EIP-155
+                // six field list
+                let protected = legacy_chain_id.is_some();
+                stream.begin_list(if protected || signature.is_some() { 9 } else { 6 });
+                self.append_body(&mut stream, self.legacy_gas_price());
+                match signature {
+                    Some(signature) => {
+                        append_legacy_v(&mut stream, signature, legacy_chain_id);
+                        stream.append(&signature.r.as_ref());
+                        stream.append(&signature.s.as_ref());
+                    }
+                    None if protected => {
+                        stream.append(&chain_id);
+                        stream.append(&0u8);
+                        stream.append(&0u8);
+                    }
+                    None => {}
+                }
+
+                stream.out().to_vec()
+            }
+        }
+    }
+
+    /// Sign the transaction with the given private key, producing the raw,
+    /// RLP encoded, signed transaction bytes ready to be broadcast with
+    /// `eth_sendRawTransaction`.
+    pub fn sign(self, key: &SecretKey, chain_id: Option<u64>) -> Result<Bytes, SignError> {
+        let unsigned = self.rlp_encode(chain_id, None);
+        let hash = keccak256(&unsigned);
+        let signature = key.sign(&hash)?;
+
+        Ok(Bytes(self.rlp_encode(chain_id, Some(&signature))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlp::Rlp;
+    use web3::types::H256;
+
+    fn key() -> SecretKey {
+        SecretKey::from_raw(&[0x11; 32]).unwrap()
+    }
+
+    fn tx(gas_price: GasPrice, access_list: Option<AccessList>) -> TransactionData {
+        TransactionData {
+            nonce: U256::from(7),
+            gas_price,
+            gas: U256::from(21_000),
+            to: Address::from_low_u64_be(0x1234),
+            value: U256::from(1_000_000_000u64),
+            data: Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
+            access_list,
+        }
+    }
+
+    /// Checks that a legacy `v` byte (as appended by `append_legacy_v`)
+    /// decodes back to an EIP-155 recovery ID of 0 or 1 for the given chain
+    /// ID, guarding against off-by-one errors in the `v` normalization.
+    fn assert_legacy_recovery_id(v: u64, chain_id: u64) {
+        let recovery_id = v
+            .checked_sub(35 + chain_id * 2)
+            .expect("v should encode a valid EIP-155 recovery id");
+        assert!(recovery_id == 0 || recovery_id == 1);
+    }
+
+    #[test]
+    fn legacy_eip155_transaction_fields_round_trip() {
+        let signed = tx(GasPrice::Legacy(U256::from(20_000_000_000u64)), None)
+            .sign(&key(), Some(1))
+            .unwrap();
+
+        let rlp = Rlp::new(&signed.0);
+        assert_eq!(rlp.item_count().unwrap(), 9);
+        assert_eq!(rlp.val_at::<U256>(0).unwrap(), U256::from(7));
+        assert_eq!(rlp.val_at::<U256>(1).unwrap(), U256::from(20_000_000_000u64));
+        assert_eq!(rlp.val_at::<U256>(2).unwrap(), U256::from(21_000));
+        assert_eq!(rlp.val_at::<Address>(3).unwrap(), Address::from_low_u64_be(0x1234));
+        assert_eq!(rlp.val_at::<U256>(4).unwrap(), U256::from(1_000_000_000u64));
+        assert_eq!(rlp.val_at::<Vec<u8>>(5).unwrap(), vec![0xde, 0xad, 0xbe, 0xef]);
+
+        assert_legacy_recovery_id(rlp.val_at::<u64>(6).unwrap(), 1);
+    }
+
+    #[test]
+    fn eip2930_access_list_transaction_fields_round_trip() {
+        let access_list = vec![web3::types::AccessListItem {
+            address: Address::from_low_u64_be(0x5678),
+            storage_keys: vec![H256::zero()],
+        }];
+        let signed = tx(GasPrice::Legacy(U256::from(20_000_000_000u64)), Some(access_list))
+            .sign(&key(), Some(1))
+            .unwrap();
+
+        assert_eq!(signed.0[0], 0x01);
+        let rlp = Rlp::new(&signed.0[1..]);
+        assert_eq!(rlp.item_count().unwrap(), 11);
+        assert_eq!(rlp.val_at::<u64>(0).unwrap(), 1);
+        assert_eq!(rlp.val_at::<U256>(1).unwrap(), U256::from(7));
+        assert_eq!(rlp.at(7).unwrap().item_count().unwrap(), 1);
+
+        // for typed transactions the `v` field is the raw 0/1 recovery id,
+        // not shifted by `append_legacy_v`.
+        let v = rlp.val_at::<u64>(8).unwrap();
+        assert!(v == 0 || v == 1);
+    }
+
+    #[test]
+    fn eip1559_transaction_fields_round_trip() {
+        let signed = tx(
+            GasPrice::Eip1559 {
+                max_fee_per_gas: U256::from(30_000_000_000u64),
+                max_priority_fee_per_gas: U256::from(2_000_000_000u64),
+            },
+            None,
+        )
+        .sign(&key(), Some(1))
+        .unwrap();
+
+        assert_eq!(signed.0[0], 0x02);
+        let rlp = Rlp::new(&signed.0[1..]);
+        assert_eq!(rlp.item_count().unwrap(), 12);
+        assert_eq!(rlp.val_at::<u64>(0).unwrap(), 1);
+        assert_eq!(rlp.val_at::<U256>(1).unwrap(), U256::from(7));
+        assert_eq!(rlp.val_at::<U256>(2).unwrap(), U256::from(2_000_000_000u64));
+        assert_eq!(rlp.val_at::<U256>(3).unwrap(), U256::from(30_000_000_000u64));
+        assert_eq!(rlp.val_at::<U256>(4).unwrap(), U256::from(21_000));
+        assert_eq!(rlp.val_at::<Address>(5).unwrap(), Address::from_low_u64_be(0x1234));
+
+        let v = rlp.val_at::<u64>(9).unwrap();
+        assert!(v == 0 || v == 1);
+    }
+}